@@ -0,0 +1,320 @@
+//! A reseeding software CSPRNG backed by a slow hardware entropy source.
+//!
+//! Hardware TRNGs such as `nrf5x::trng::Trng` produce only a handful of
+//! bytes per interrupt, which is too slow for consumers that need kilobytes
+//! of randomness (e.g. generating several session keys). Following the
+//! approach of `rand`'s `ReseedingRng`/`thread_rng`, this capsule wraps a
+//! fast ChaCha20 keystream generator: it is seeded (keyed) from the
+//! underlying `rng::RNG`, serves `get`/`randomness_available` requests
+//! directly from the keystream, and only goes back to the hardware source
+//! to rekey once `RESEED_INTERVAL` bytes of output have been produced.
+
+use core::cell::Cell;
+use kernel::hil::rng::{self, Continue};
+
+/// ChaCha20 key length, in bytes.
+pub const KEY_LEN: usize = 32;
+/// ChaCha20 nonce length, in bytes.
+pub const NONCE_LEN: usize = 8;
+/// Total number of hardware-entropy bytes requested per reseed.
+pub const SEED_LEN: usize = KEY_LEN + NONCE_LEN;
+
+/// Number of keystream bytes to hand out before requesting a fresh seed
+/// from the hardware TRNG.
+pub const RESEED_INTERVAL: usize = 32 * 1024;
+
+/// Scratch buffer used to request a fresh seed from the hardware `RNG`.
+static mut SEED_BUF: [u8; SEED_LEN] = [0; SEED_LEN];
+
+const CHACHA_ROUNDS: usize = 20;
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] ^= state[a];
+    state[d] = state[d].rotate_left(16);
+
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] ^= state[c];
+    state[b] = state[b].rotate_left(12);
+
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] ^= state[a];
+    state[d] = state[d].rotate_left(8);
+
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] ^= state[c];
+    state[b] = state[b].rotate_left(7);
+}
+
+/// Run the ChaCha20 core function over `key`/`nonce` at block `counter`,
+/// producing 16 words (64 bytes) of keystream.
+fn chacha20_block(key: &[u32; 8], nonce: &[u32; 2], counter: u32) -> [u32; 16] {
+    const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+    let mut state = [0u32; 16];
+    state[0..4].copy_from_slice(&CONSTANTS);
+    state[4..12].copy_from_slice(key);
+    state[12] = counter;
+    state[13] = 0;
+    state[14..16].copy_from_slice(nonce);
+
+    let mut working = state;
+    for _ in 0..(CHACHA_ROUNDS / 2) {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+
+    for i in 0..16 {
+        working[i] = working[i].wrapping_add(state[i]);
+    }
+    working
+}
+
+struct BlockIter<'a>(&'a [u32], usize);
+
+impl<'a> Iterator for BlockIter<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.1 >= self.0.len() {
+            return None;
+        }
+        let word = self.0[self.1];
+        self.1 += 1;
+        Some(word)
+    }
+}
+
+/// A fast, reseeding CSPRNG that multiplexes a slow hardware entropy
+/// source across any number of client capsules.
+pub struct ReseedingRng<'a> {
+    trng: &'a rng::RNG,
+    client: Cell<Option<&'a rng::Client>>,
+    buffer_client: Cell<Option<&'a rng::BufferClient>>,
+    key: Cell<[u32; 8]>,
+    nonce: Cell<[u32; 2]>,
+    counter: Cell<u32>,
+    bytes_until_reseed: Cell<usize>,
+    reseeding: Cell<bool>,
+    reseed_failures: Cell<usize>,
+    /// A `get_bytes` request that arrived while a reseed was needed or
+    /// already in flight, along with how much of it has been filled so
+    /// far. Resumed from `randomness_received` once the reseed completes,
+    /// the same way `get`'s word-oriented client is resumed via `self.get()`.
+    pending: Cell<Option<(&'static mut [u8], usize, usize)>>,
+}
+
+/// Give up retrying a persistently failing hardware source after this many
+/// consecutive rejected seed requests, rather than hammering it forever.
+const MAX_RESEED_ATTEMPTS: usize = 3;
+
+impl<'a> ReseedingRng<'a> {
+    pub const fn new(trng: &'a rng::RNG) -> ReseedingRng<'a> {
+        ReseedingRng {
+            trng: trng,
+            client: Cell::new(None),
+            buffer_client: Cell::new(None),
+            key: Cell::new([0; 8]),
+            nonce: Cell::new([0; 2]),
+            counter: Cell::new(0),
+            // Force a seed from the hardware TRNG before the first block
+            // is ever produced.
+            bytes_until_reseed: Cell::new(0),
+            reseeding: Cell::new(false),
+            reseed_failures: Cell::new(0),
+            pending: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a rng::Client) {
+        self.client.set(Some(client));
+    }
+
+    pub fn set_buffer_client(&self, client: &'a rng::BufferClient) {
+        self.buffer_client.set(Some(client));
+    }
+
+    /// Copy `word` into `buf`, little-endian, truncating to however much
+    /// of the word fits.
+    fn write_word(buf: &mut [u8], word: u32) {
+        let bytes = [
+            word as u8,
+            (word >> 8) as u8,
+            (word >> 16) as u8,
+            (word >> 24) as u8,
+        ];
+        let take = core::cmp::min(4, buf.len());
+        buf[..take].copy_from_slice(&bytes[..take]);
+    }
+
+    fn reseed(&self) {
+        self.reseeding.set(true);
+        let buf = unsafe { &mut SEED_BUF[..] };
+        self.trng.get_bytes(buf, SEED_LEN);
+    }
+
+    fn next_block(&self) -> [u32; 16] {
+        let key = self.key.get();
+        let nonce = self.nonce.get();
+        let counter = self.counter.get();
+        self.counter.set(counter.wrapping_add(1));
+        self.bytes_until_reseed
+            .set(self.bytes_until_reseed.get().saturating_sub(64));
+        chacha20_block(&key, &nonce, counter)
+    }
+
+    /// Resume a `get_bytes` request that was stashed because a reseed was
+    /// needed, filling as much of it as the current keystream allows. If
+    /// the request outlives `RESEED_INTERVAL` bytes or a reseed is still
+    /// in flight, the remainder is stashed again and this returns early;
+    /// it is called again from `randomness_received` once the reseed
+    /// finishes.
+    fn drain_pending(&self) {
+        if self.reseeding.get() {
+            // Will resume once the in-flight reseed completes.
+            return;
+        }
+
+        let (buf, len, mut produced) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        let mut buf = buf;
+
+        while produced < len {
+            if self.bytes_until_reseed.get() == 0 {
+                // Ran out of keystream mid-request: stash the remainder
+                // and rekey before continuing, instead of generating more
+                // blocks from the stale key.
+                self.pending.set(Some((buf, len, produced)));
+                self.reseed();
+                return;
+            }
+
+            let block = self.next_block();
+            for &word in block.iter() {
+                if produced >= len {
+                    break;
+                }
+                let take = core::cmp::min(4, len - produced);
+                ReseedingRng::write_word(&mut buf[produced..produced + take], word);
+                produced += take;
+            }
+        }
+
+        self.buffer_client
+            .get()
+            .map(|client| client.randomness_received(buf, len));
+    }
+}
+
+impl<'a> rng::RNG for ReseedingRng<'a> {
+    fn get(&self) {
+        if self.reseeding.get() {
+            // A reseed is already in flight; the client will be serviced
+            // once `randomness_received` completes it.
+            return;
+        }
+
+        loop {
+            if self.bytes_until_reseed.get() == 0 {
+                self.reseed();
+                return;
+            }
+
+            let client = match self.client.get() {
+                Some(client) => client,
+                None => return,
+            };
+
+            let block = self.next_block();
+            let result = client.randomness_available(&mut BlockIter(&block, 0));
+            if Continue::Done == result {
+                return;
+            }
+            // Client wants more: loop instead of recursing, since block
+            // generation here is synchronous (unlike the hardware TRNG,
+            // which only "recurses" on the next interrupt).
+        }
+    }
+
+    fn get_bytes(&self, buf: &'static mut [u8], len: usize) {
+        let len = core::cmp::min(len, buf.len());
+
+        // Stash the request and (re)start draining it. If a reseed is
+        // needed or already in flight, `drain_pending` leaves it stashed
+        // and `randomness_received` resumes it once the reseed completes —
+        // mirroring how `get`'s word-oriented client is deferred and
+        // resumed via `self.get()`, instead of bouncing the caller with an
+        // error the underlying hardware never actually produced.
+        self.pending.set(Some((buf, len, 0)));
+        self.drain_pending();
+    }
+}
+
+impl<'a> rng::BufferClient for ReseedingRng<'a> {
+    fn randomness_error(&self, _buffer: &'static mut [u8], error: rng::Error) {
+        // The hardware source rejected the seed request (a failed health
+        // test, or it was busy with another request). Retry a bounded
+        // number of times before giving up, rather than hammering a
+        // persistently failing source forever.
+        let failures = self.reseed_failures.get() + 1;
+        if failures < MAX_RESEED_ATTEMPTS {
+            self.reseed_failures.set(failures);
+            self.reseed();
+            return;
+        }
+
+        // Retry budget exhausted: stop and tell whichever client(s) are
+        // waiting that no randomness is available, rather than looping
+        // silently. A later call to `get`/`get_bytes` will trigger another
+        // reseed attempt.
+        self.reseed_failures.set(0);
+        self.reseeding.set(false);
+        self.client
+            .get()
+            .map(|client| client.randomness_error(error));
+        if let Some((buf, _len, _produced)) = self.pending.take() {
+            self.buffer_client
+                .get()
+                .map(|client| client.randomness_error(buf, error));
+        }
+    }
+
+    fn randomness_received(&self, buffer: &'static mut [u8], len: usize) {
+        let mut key = [0u32; 8];
+        let mut nonce = [0u32; 2];
+        for i in 0..8 {
+            key[i] = (buffer[4 * i] as u32) | (buffer[4 * i + 1] as u32) << 8
+                | (buffer[4 * i + 2] as u32) << 16
+                | (buffer[4 * i + 3] as u32) << 24;
+        }
+        for i in 0..2 {
+            let o = KEY_LEN + 4 * i;
+            nonce[i] = (buffer[o] as u32) | (buffer[o + 1] as u32) << 8
+                | (buffer[o + 2] as u32) << 16 | (buffer[o + 3] as u32) << 24;
+        }
+        let _ = len;
+
+        self.key.set(key);
+        self.nonce.set(nonce);
+        self.counter.set(0);
+        self.bytes_until_reseed.set(RESEED_INTERVAL);
+        self.reseeding.set(false);
+        self.reseed_failures.set(0);
+
+        // Resume whichever request(s) triggered this reseed: a stashed
+        // `get_bytes` buffer first, then the word-oriented client.
+        self.drain_pending();
+        if self.client.get().is_some() {
+            self.get();
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! A self-contained software entropy source for boards without a hardware
+//! TRNG.
+//!
+//! Ports the core idea of `rand`'s `JitterRng`: repeatedly run a small,
+//! fixed amount of work (a memory-access "walk" over a scratch buffer,
+//! each visited byte updated by an add-one-and-rotate step; no LFSR
+//! feedback is applied) and fold the low bit of how long
+//! each iteration took, as measured against a monotonic kernel
+//! timer/cycle counter, into a 64-bit accumulator via rotate-and-xor.
+//! Because the timing of each iteration is perturbed by CPU and memory
+//! jitter that isn't predictable from the outside, each fold contributes
+//! roughly one bit of entropy; once enough rounds have been folded, the
+//! low word of the accumulator is delivered as one generated word.
+//!
+//! `JitterRng` delivers words through the same `rng::Client` interface as
+//! `nrf5x::trng::Trng`, so it is a drop-in `rng::RNG` for chips that have
+//! no other entropy source.
+
+use core::cell::Cell;
+use kernel::hil::rng::{self, Continue};
+use kernel::hil::time::Time;
+
+/// Number of memory-walk steps run per timing measurement.
+const WALK_STEPS: usize = 4;
+/// Size of the scratch buffer walked on each iteration; small enough that
+/// its accesses are sensitive to cache/memory timing jitter.
+const WALK_SIZE: usize = 64;
+/// Number of timing measurements folded together to assemble one output
+/// word.
+const FOLDS_PER_WORD: usize = 32;
+
+/// Minimum difference, in timer ticks, between the durations of two
+/// consecutive rounds, below which the pair is considered to show no
+/// measurable jitter.
+const MIN_ENTROPY_DELTA: u32 = 1;
+
+/// Harvests entropy from CPU/memory timing jitter using a monotonic
+/// `Time` source, rather than a dedicated hardware random number
+/// generator.
+pub struct JitterRng<'a> {
+    timer: &'a Time,
+    client: Cell<Option<&'a rng::Client>>,
+    buffer_client: Cell<Option<&'a rng::BufferClient>>,
+    accumulator: Cell<u64>,
+    walk: Cell<[u8; WALK_SIZE]>,
+    walk_index: Cell<usize>,
+    startup_tested: Cell<bool>,
+}
+
+impl<'a> JitterRng<'a> {
+    pub const fn new(timer: &'a Time) -> JitterRng<'a> {
+        JitterRng {
+            timer: timer,
+            client: Cell::new(None),
+            buffer_client: Cell::new(None),
+            accumulator: Cell::new(0),
+            walk: Cell::new([0; WALK_SIZE]),
+            walk_index: Cell::new(0),
+            startup_tested: Cell::new(false),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a rng::Client) {
+        self.client.set(Some(client));
+    }
+
+    pub fn set_buffer_client(&self, client: &'a rng::BufferClient) {
+        self.buffer_client.set(Some(client));
+    }
+
+    /// Copy `word` into `buf`, little-endian, truncating to however much
+    /// of the word fits.
+    fn write_word(buf: &mut [u8], word: u32) {
+        let bytes = [
+            word as u8,
+            (word >> 8) as u8,
+            (word >> 16) as u8,
+            (word >> 24) as u8,
+        ];
+        let take = core::cmp::min(4, buf.len());
+        buf[..take].copy_from_slice(&bytes[..take]);
+    }
+
+    /// Run one memory-access walk over the scratch buffer and return how
+    /// long it took, in timer ticks.
+    fn time_one_round(&self) -> u32 {
+        let start = self.timer.now();
+
+        let mut walk = self.walk.get();
+        let mut index = self.walk_index.get();
+        for _ in 0..WALK_STEPS {
+            index = (index + 1 + walk[index] as usize) % WALK_SIZE;
+            walk[index] = walk[index].wrapping_add(1).rotate_left(1);
+        }
+        self.walk.set(walk);
+        self.walk_index.set(index);
+
+        let end = self.timer.now();
+        end.wrapping_sub(start)
+    }
+
+    /// Fold one measurement's low bit into the 64-bit accumulator.
+    fn fold(&self, delta: u32) {
+        let bit = (delta & 1) as u64;
+        let acc = self.accumulator.get().rotate_left(1) ^ bit;
+        self.accumulator.set(acc);
+    }
+
+    /// Run the `JitterRng` startup test: fold a word's worth of
+    /// measurements and report whether any of them showed measurable
+    /// jitter. Boards whose timer lacks enough resolution fail this
+    /// outright rather than silently handing out predictable values.
+    fn startup_test(&self) -> bool {
+        let mut observed_entropy = false;
+        let mut prev_delta = None;
+        for _ in 0..FOLDS_PER_WORD {
+            let delta = self.time_one_round();
+            if let Some(prev) = prev_delta {
+                let variation = if delta > prev { delta - prev } else { prev - delta };
+                if variation >= MIN_ENTROPY_DELTA {
+                    observed_entropy = true;
+                }
+            }
+            prev_delta = Some(delta);
+            self.fold(delta);
+        }
+        observed_entropy
+    }
+
+    fn next_word(&self) -> u32 {
+        for _ in 0..FOLDS_PER_WORD {
+            let delta = self.time_one_round();
+            self.fold(delta);
+        }
+        self.accumulator.get() as u32
+    }
+}
+
+impl<'a> rng::RNG for JitterRng<'a> {
+    fn get(&self) {
+        if !self.startup_tested.get() {
+            if !self.startup_test() {
+                self.client.get().map(|client| {
+                    client.randomness_error(rng::Error::InsufficientEntropy);
+                });
+                return;
+            }
+            self.startup_tested.set(true);
+        }
+
+        loop {
+            let client = match self.client.get() {
+                Some(client) => client,
+                None => return,
+            };
+
+            let word = self.next_word();
+            let result = client.randomness_available(&mut core::iter::once(word));
+            if Continue::Done == result {
+                return;
+            }
+            // Client wants more: loop instead of recursing, since each
+            // word is produced synchronously here rather than across
+            // interrupts.
+        }
+    }
+
+    fn get_bytes(&self, buf: &'static mut [u8], len: usize) {
+        if !self.startup_tested.get() {
+            if !self.startup_test() {
+                self.buffer_client
+                    .get()
+                    .map(|client| client.randomness_error(buf, rng::Error::InsufficientEntropy));
+                return;
+            }
+            self.startup_tested.set(true);
+        }
+
+        let len = core::cmp::min(len, buf.len());
+        let mut buf = buf;
+        let mut produced = 0;
+        while produced < len {
+            let word = self.next_word();
+            let take = core::cmp::min(4, len - produced);
+            JitterRng::write_word(&mut buf[produced..produced + take], word);
+            produced += take;
+        }
+
+        self.buffer_client
+            .get()
+            .map(|client| client.randomness_received(buf, len));
+    }
+}
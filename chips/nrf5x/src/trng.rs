@@ -3,20 +3,24 @@
 //! The TRNG generates 1 byte randomness at the time value in the interval
 //! 0 <= r <= 255
 //!
-//! The capsule requires 4 bytes of randomness
-//!
-//! The counter "done" ensures that 4 bytes of randomness have been generated
-//! before returning to the capsule.
-//!
-//! A temporary array "randomness" is used to store the randomness until it is
-//! returned to the capsule
-//!
-//! In the current implementation if done > 4 for some strange reason the
-//! random generation will be restarted
+//! The driver fills an arbitrary-length, caller-supplied buffer one byte at
+//! a time, restarting the peripheral after each VALRDY interrupt until the
+//! requested number of bytes has been collected. The legacy word-oriented
+//! `get`/`Client` interface is implemented on top of the same engine using a
+//! fixed 4-byte scratch buffer, so callers that need more than a single word
+//! (e.g. a 32-byte key) can use `get_bytes` directly instead of calling
+//! `get()` repeatedly.
 //!
 //! Author: Niklas Adolfsson <niklasadolfsson1@gmail.com>
 //! Author: Fredrik Nilsson <frednils@student.chalmers.se>
 //! Date: March 01, 2017
+//!
+//! Every byte read from the peripheral is also run through a software
+//! continuous health test (loosely modelled on the NIST SP 800-90B
+//! repetition count and adaptive proportion tests) before it is handed to
+//! a client, so a failing oscillator is reported via
+//! `rng::Client::randomness_error`/`rng::BufferClient::randomness_error`
+//! rather than silently producing suspect randomness.
 
 use core::cell::Cell;
 use kernel::hil::rng::{self, Continue};
@@ -24,25 +28,119 @@ use nvic;
 use peripheral_interrupts::NvicIdx;
 use peripheral_registers::{RNG_BASE, RNG_REGS};
 
+/// Scratch buffer backing the legacy word-oriented `get()`/`Client` API.
+static mut WORD_BUF: [u8; 4] = [0; 4];
+
+/// Repetition count test cutoff: fail if the same byte is read more than
+/// this many times in a row. Approximates ">64 consecutive identical bits"
+/// at byte granularity.
+const REPETITION_CUTOFF: usize = 8;
+
+/// Adaptive proportion test window size, in bytes.
+const ADAPTIVE_WINDOW_SIZE: usize = 512;
+
+/// Adaptive proportion test cutoff: fail if the first byte of a window
+/// recurs more than this many times within the window. A conservative
+/// approximation of the NIST SP 800-90B binomial cutoff table, not the
+/// exact tabulated value.
+const ADAPTIVE_CUTOFF: usize = 327;
+
 pub struct Trng<'a> {
     regs: *const RNG_REGS,
     client: Cell<Option<&'a rng::Client>>,
+    buffer_client: Cell<Option<&'a rng::BufferClient>>,
+    buffer: Cell<Option<&'static mut [u8]>>,
     index: Cell<usize>,
-    randomness: Cell<u32>,
+    len: Cell<usize>,
+    have_prev_byte: Cell<bool>,
+    prev_byte: Cell<u8>,
+    rep_count: Cell<usize>,
+    window_first: Cell<u8>,
+    window_matches: Cell<usize>,
+    window_count: Cell<usize>,
+    bias_correction: Cell<bool>,
+    busy: Cell<bool>,
 }
 
-pub static mut TRNG: Trng<'static> = Trng::new();
+// Boards that need corrected (rather than raw, higher-throughput) entropy
+// can flip this to `true`.
+pub static mut TRNG: Trng<'static> = Trng::new(false);
 
 impl<'a> Trng<'a> {
-    const fn new() -> Trng<'a> {
+    const fn new(bias_correction: bool) -> Trng<'a> {
         Trng {
             regs: RNG_BASE as *const RNG_REGS,
             client: Cell::new(None),
+            buffer_client: Cell::new(None),
+            buffer: Cell::new(None),
             index: Cell::new(0),
-            randomness: Cell::new(0),
+            len: Cell::new(0),
+            have_prev_byte: Cell::new(false),
+            prev_byte: Cell::new(0),
+            rep_count: Cell::new(0),
+            window_first: Cell::new(0),
+            window_matches: Cell::new(0),
+            window_count: Cell::new(0),
+            bias_correction: Cell::new(bias_correction),
+            busy: Cell::new(false),
         }
     }
 
+    /// Enable or disable the peripheral's digital error correction
+    /// (bias-correction) mode, which removes statistical bias from the raw
+    /// oscillator output at the cost of lower throughput. The register is
+    /// written immediately and re-applied before each byte is acquired, so
+    /// the new mode also covers the remaining bytes of a request in flight.
+    pub fn set_bias_correction(&self, enabled: bool) {
+        self.bias_correction.set(enabled);
+        let regs = unsafe { &*self.regs };
+        regs.config.set(if enabled { 1 } else { 0 });
+    }
+
+    /// Run the continuous health test over a freshly read byte, updating
+    /// the repetition count and adaptive proportion test state. Returns
+    /// `Err` with the failing test if the byte should not be trusted.
+    fn health_test(&self, byte: u8) -> Result<(), rng::Error> {
+        // Repetition Count Test
+        if self.have_prev_byte.get() && byte == self.prev_byte.get() {
+            let count = self.rep_count.get() + 1;
+            self.rep_count.set(count);
+            if count > REPETITION_CUTOFF {
+                return Err(rng::Error::RepetitionCount);
+            }
+        } else {
+            self.rep_count.set(1);
+        }
+        self.prev_byte.set(byte);
+        self.have_prev_byte.set(true);
+
+        // Adaptive Proportion Test
+        if self.window_count.get() == 0 {
+            self.window_first.set(byte);
+            self.window_matches.set(1);
+            self.window_count.set(1);
+            return Ok(());
+        }
+
+        let matches = if byte == self.window_first.get() {
+            self.window_matches.get() + 1
+        } else {
+            self.window_matches.get()
+        };
+        let count = self.window_count.get() + 1;
+        if count >= ADAPTIVE_WINDOW_SIZE {
+            self.window_count.set(0);
+            self.window_matches.set(0);
+            if matches > ADAPTIVE_CUTOFF {
+                return Err(rng::Error::AdaptiveProportion);
+            }
+        } else {
+            self.window_matches.set(matches);
+            self.window_count.set(count);
+        }
+        Ok(())
+    }
+
     // only VALRDY register can trigger the interrupt
     pub fn handle_interrupt(&self) {
         let regs = unsafe { &*self.regs };
@@ -51,38 +149,58 @@ impl<'a> Trng<'a> {
         self.disable_nvic();
         nvic::clear_pending(NvicIdx::RNG);
 
-        match self.index.get() {
-            // fetch more data need 4 bytes because the capsule requires that
-            e @ 0...3 => {
-                // 3 lines below to change data in Cell, perhaps it can be done more nicely
-                let mut rn = self.randomness.get();
-                // 1 byte randomness
-                let r = regs.value.get();
-                //  e = 0 -> byte 1 LSB
-                //  e = 1 -> byte 2
-                //  e = 2 -> byte 3
-                //  e = 3 -> byte 4 MSB
-                rn |= r << 8 * e;
-                self.randomness.set(rn);
-
-                self.index.set(e + 1);
-                self.start_rng()
+        let mut buf = match self.buffer.take() {
+            Some(buf) => buf,
+            // This should never happen if the logic is correct.
+            None => return,
+        };
+        // `get` always uses WORD_BUF; any other buffer came from `get_bytes`.
+        let word_request = buf.as_ptr() == unsafe { WORD_BUF.as_ptr() };
+
+        // 1 byte randomness
+        let byte = regs.value.get() as u8;
+
+        if let Err(error) = self.health_test(byte) {
+            self.index.set(0);
+            self.len.set(0);
+            self.busy.set(false);
+            if word_request {
+                if let Some(client) = self.client.get() {
+                    client.randomness_error(error);
+                }
+            } else if let Some(client) = self.buffer_client.get() {
+                client.randomness_error(buf, error);
             }
-            // fetched 4 bytes of data send to the capsule
-            4 => {
-                self.client.get().map(|client| {
-                    let result = client.randomness_available(&mut TrngIter(self));
-                    if Continue::Done != result {
-                        // need more randomness i.e generate more randomness
-                        self.start_rng();
-                    }
-                });
+            return;
+        }
+
+        let index = self.index.get();
+        buf[index] = byte;
+        let index = index + 1;
+
+        let len = self.len.get();
+        if index < len {
+            // need more bytes to fill the buffer
+            self.index.set(index);
+            self.buffer.set(Some(buf));
+            self.start_rng();
+            return;
+        }
+
+        // filled the requested number of bytes, hand them back
+        self.index.set(0);
+        self.len.set(0);
+        self.busy.set(false);
+
+        if !word_request {
+            if let Some(client) = self.buffer_client.get() {
+                client.randomness_received(buf, len);
             }
-            // This should never happen if the logic is correct
-            // Restart randomness generation if the condition occurs
-            _ => {
-                self.index.set(0);
-                self.randomness.set(0);
+        } else if let Some(client) = self.client.get() {
+            let result = client.randomness_available(&mut TrngIter(buf));
+            if Continue::Done != result {
+                // need more randomness i.e generate more randomness
+                self.get();
             }
         }
     }
@@ -91,6 +209,10 @@ impl<'a> Trng<'a> {
         self.client.set(Some(client));
     }
 
+    pub fn set_buffer_client(&self, client: &'a rng::BufferClient) {
+        self.buffer_client.set(Some(client));
+    }
+
     fn enable_interrupts(&self) {
         let regs = unsafe { &*self.regs };
         regs.inten.set(1);
@@ -111,12 +233,26 @@ impl<'a> Trng<'a> {
         nvic::disable(NvicIdx::RNG);
     }
 
+    /// Park `buf` as the in-flight request and kick off acquisition.
+    /// Callers must have already checked `self.busy` is clear.
+    fn start_request(&self, buf: &'static mut [u8], len: usize) {
+        let len = core::cmp::min(len, buf.len());
+        self.busy.set(true);
+        self.index.set(0);
+        self.len.set(len);
+        self.buffer.set(Some(buf));
+        self.start_rng();
+    }
+
     fn start_rng(&self) {
         let regs = unsafe { &*self.regs };
 
         // clear registers
         regs.event_valrdy.set(0);
 
+        // apply the configured bias-correction mode
+        regs.config.set(if self.bias_correction.get() { 1 } else { 0 });
+
         // enable interrupts
         self.enable_nvic();
         self.enable_interrupts();
@@ -126,26 +262,55 @@ impl<'a> Trng<'a> {
     }
 }
 
-struct TrngIter<'a, 'b: 'a>(&'a Trng<'b>);
+struct TrngIter(&'static mut [u8]);
 
-impl<'a, 'b> Iterator for TrngIter<'a, 'b> {
+impl Iterator for TrngIter {
     type Item = u32;
 
     fn next(&mut self) -> Option<u32> {
-        if self.0.index.get() == 4 {
-            let rn = self.0.randomness.get();
-            // indicate 4 bytes of randomness taken by the capsule
-            self.0.index.set(0);
-            self.0.randomness.set(0);
-            Some(rn)
-        } else {
-            None
+        if self.0.len() < 4 {
+            return None;
         }
+        let rn = (self.0[0] as u32) | (self.0[1] as u32) << 8 | (self.0[2] as u32) << 16
+            | (self.0[3] as u32) << 24;
+        self.0 = &mut [];
+        Some(rn)
     }
 }
 
 impl<'a> rng::RNG for Trng<'a> {
     fn get(&self) {
-        self.start_rng()
+        if self.busy.get() {
+            // A request is already in flight; reject this one rather than
+            // clobber the buffer reference it is using. Notify whichever
+            // client slot(s) are populated, not just the one matching
+            // this call, since a `Client` and `BufferClient` can be
+            // registered at the same time and either could be the
+            // caller waiting on a response.
+            self.client
+                .get()
+                .map(|client| client.randomness_error(rng::Error::Busy));
+            self.buffer_client
+                .get()
+                .map(|client| client.randomness_error(&mut [], rng::Error::Busy));
+            return;
+        }
+        let buf = unsafe { &mut WORD_BUF[..] };
+        self.start_request(buf, 4);
+    }
+
+    fn get_bytes(&self, buf: &'static mut [u8], len: usize) {
+        if self.busy.get() {
+            // See the comment in `get`: notify both client slots, since
+            // either could be the one actually waiting.
+            self.client
+                .get()
+                .map(|client| client.randomness_error(rng::Error::Busy));
+            self.buffer_client
+                .get()
+                .map(|client| client.randomness_error(buf, rng::Error::Busy));
+            return;
+        }
+        self.start_request(buf, len);
     }
 }
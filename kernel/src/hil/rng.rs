@@ -0,0 +1,78 @@
+//! Interfaces for hardware random number generators.
+
+/// Denotes whether the [`RNG`](trait.RNG.html) should continue requesting
+/// random numbers or whether it has received enough.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Continue {
+    /// More randomness is required.
+    More,
+    /// Enough randomness has been received.
+    Done,
+}
+
+/// Generic interface for a hardware random number generator.
+pub trait RNG {
+    /// Initiate the generation of random numbers. Once some randomness is
+    /// ready, the registered `Client`'s `randomness_available` callback is
+    /// called (possibly more than once) with the generated words.
+    fn get(&self);
+
+    /// Request `len` bytes of randomness, written directly into `buf`. Once
+    /// `buf` has been filled it is handed back to the caller through
+    /// [`BufferClient::randomness_received`](trait.BufferClient.html#tymethod.randomness_received).
+    ///
+    /// This lets a caller obtain a large, arbitrary-length block of
+    /// randomness (e.g. a key) in one request instead of draining a
+    /// word-at-a-time `Client` callback repeatedly.
+    fn get_bytes(&self, buf: &'static mut [u8], len: usize);
+}
+
+/// Reason an `RNG` request was rejected instead of completed normally.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Error {
+    /// Too many consecutive samples repeated the same value (NIST SP
+    /// 800-90B repetition count test).
+    RepetitionCount,
+    /// One value occurred too often within a sliding window of samples
+    /// (NIST SP 800-90B adaptive proportion test).
+    AdaptiveProportion,
+    /// A software entropy source's startup test measured too little
+    /// timing jitter (or similar) to trust its output.
+    InsufficientEntropy,
+    /// The generator already has a request in flight and cannot accept
+    /// another until it completes. Unlike the other variants, this says
+    /// nothing about the trustworthiness of the source — a client may
+    /// simply retry once the in-flight request finishes.
+    Busy,
+}
+
+/// Implement to receive randomness word-at-a-time from an `RNG::get`
+/// request.
+pub trait Client {
+    /// Called with an iterator of freshly generated random words. Return
+    /// `Continue::More` to request additional randomness once the iterator
+    /// is drained, or `Continue::Done` once enough has been received.
+    fn randomness_available(&self, randomness: &mut Iterator<Item = u32>) -> Continue;
+
+    /// Called instead of `randomness_available` when the request was
+    /// rejected; `_error` says why (a failed health or startup test, or
+    /// `Error::Busy`). No randomness is delivered for this cycle; after a
+    /// test failure a well-behaved client should refuse to use or seed from
+    /// this source until it recovers.
+    fn randomness_error(&self, _error: Error) {}
+}
+
+/// Implement to receive randomness from an `RNG::get_bytes` request.
+pub trait BufferClient {
+    /// Called once `buffer` has been filled with `len` bytes of randomness.
+    fn randomness_received(&self, buffer: &'static mut [u8], len: usize);
+
+    /// Called instead of `randomness_received` when the request was
+    /// rejected before `buffer` could be filled (a failed health or startup
+    /// test, or `Error::Busy`). The request's buffer is returned so the
+    /// caller can reuse it; a rejection not tied to a `get_bytes` buffer
+    /// passes an empty slice.
+    fn randomness_error(&self, buffer: &'static mut [u8], _error: Error) {
+        let _ = buffer;
+    }
+}
@@ -0,0 +1,8 @@
+//! Interface for a monotonic, free-running hardware timer.
+
+/// A monotonically increasing counter, typically backed by a cycle
+/// counter or free-running timer peripheral. Wraps on overflow.
+pub trait Time {
+    /// Return the current value of the counter.
+    fn now(&self) -> u32;
+}